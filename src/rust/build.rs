@@ -7,6 +7,7 @@
 //! configure script, and then building a set of options for cargo to pass to
 //! the compiler.
 
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::env;
 use std::fs::File;
@@ -14,26 +15,89 @@ use std::io::prelude::*;
 use std::io;
 use std::path::PathBuf;
 
-/// Wrapper around a key-value map.
-struct Config(
-    HashMap<String,String>
-);
+/// Wrapper around a key-value map, plus a buffer of pending `cargo:`
+/// directives, deduped and flushed to stdout by `flush()`.
+struct Config {
+    vars : HashMap<String,String>,
+    directives : RefCell<Vec<String>>,
+    /// Path to the config.rust file this was loaded from.
+    config_path : String,
+    /// Every environment variable consulted while locating and loading
+    /// config.rust, or read later through `env_var()`, so that `main()`
+    /// can tell cargo to re-run us if any of them change.
+    env_vars : RefCell<Vec<String>>,
+}
+
+/// Split a flags string into whitespace-separated tokens, the way a shell
+/// would: whitespace inside a quoted ('...' or "...") substring does not
+/// split a token.
+fn tokenize_flags(s : &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut cur = String::new();
+    let mut in_token = false;
+    let mut quote = None;
+    for c in s.chars() {
+        match quote {
+            Some(q) if c == q => {
+                quote = None;
+            },
+            Some(_) => {
+                cur.push(c);
+            },
+            None if c == '\'' || c == '"' => {
+                quote = Some(c);
+                in_token = true;
+            },
+            None if c.is_whitespace() => {
+                if in_token {
+                    tokens.push(cur.clone());
+                    cur.clear();
+                    in_token = false;
+                }
+            },
+            None => {
+                cur.push(c);
+                in_token = true;
+            }
+        }
+    }
+    if in_token {
+        tokens.push(cur);
+    }
+    tokens
+}
+
+/// Locate a config.rust file: check each directory in the colon-separated
+/// `TOR_RUST_CONFIG_PATH`, then fall back to recursing up from OUT_DIR.
+/// Appends every environment variable consulted to `env_vars`.
+fn find_cfg(env_vars : &mut Vec<String>) -> io::Result<String> {
+    let mut searched = Vec::new();
+
+    env_vars.push("TOR_RUST_CONFIG_PATH".to_owned());
+    if let Ok(rust_path) = env::var("TOR_RUST_CONFIG_PATH") {
+        for dir in rust_path.split(':') {
+            let mut path = PathBuf::from(dir);
+            path.push("config.rust");
+            if path.exists() {
+                return Ok(path.to_str().unwrap().to_owned());
+            }
+            searched.push(path.to_str().unwrap().to_owned());
+        }
+    }
 
-/// Locate a config.rust file generated by autoconf, starting in the OUT_DIR
-/// location provided by cargo and recursing up the directory tree.  Note that
-/// we need to look in the OUT_DIR, since autoconf will place generated files
-/// in the build directory.
-fn find_cfg() -> io::Result<String> {
+    env_vars.push("OUT_DIR".to_owned());
     let mut path = PathBuf::from(env::var("OUT_DIR").unwrap());
     loop {
         path.push("config.rust");
+        searched.push(path.to_str().unwrap().to_owned());
         if path.exists() {
             return Ok(path.to_str().unwrap().to_owned());
         }
         path.pop(); // remove config.rust
         if ! path.pop() { // can't remove last part of directory
-            return Err(io::Error::new(io::ErrorKind::NotFound,
-                                      "No config.rust"));
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No config.rust found; searched: {}", searched.join(", "))));
         }
     }
 }
@@ -44,7 +108,8 @@ impl Config {
     /// The file format is a series of lines of the form KEY=VAL, with
     /// any blank lines and lines starting with # ignored.
     fn load() -> io::Result<Config> {
-        let path = find_cfg()?;
+        let mut env_vars = Vec::new();
+        let path = find_cfg(&mut env_vars)?;
         let f = File::open(&path)?;
         let reader = io::BufReader::new(f);
         let mut map = HashMap::new();
@@ -64,57 +129,153 @@ impl Config {
             let val = &eq_val[1..];
             map.insert(var.to_owned(), val.to_owned());
         }
-        Ok(Config(map))
+        Ok(Config {
+            vars: map,
+            directives: RefCell::new(Vec::new()),
+            config_path: path,
+            env_vars: RefCell::new(env_vars),
+        })
+    }
+
+    /// Read an environment variable, recording its name so that
+    /// `emit_rerun_if_changed` can later tell cargo to re-run the build
+    /// script if it changes.
+    fn env_var(&self, name : &str) -> Option<String> {
+        self.env_vars.borrow_mut().push(name.to_owned());
+        env::var(name).ok()
+    }
+
+    /// Tell cargo to re-run this script if config.rust, or any
+    /// environment variable consulted while loading it or read through
+    /// `env_var()`, changes.
+    fn emit_rerun_if_changed(&self) {
+        println!("cargo:rerun-if-changed={}", self.config_path);
+        for var in self.env_vars.borrow().iter() {
+            println!("cargo:rerun-if-env-changed={}", var);
+        }
+    }
+
+    /// Which component set to link: "testing" (the `*-testing`
+    /// components) or "production" (the plain ones).  Set explicitly via
+    /// `RUST_LINK_PROFILE`, since no cargo-provided signal distinguishes
+    /// a test build; defaults to "production".
+    fn link_profile(&self) -> String {
+        match self.env_var("RUST_LINK_PROFILE") {
+            Some(p) => p,
+            None => "production".to_owned(),
+        }
     }
 
     /// Return a reference to the value whose key is 'key'.
     ///
     /// Panics if 'key' is not found in the configuration.
     fn get(&self, key : &str) -> &str {
-        self.0.get(key).unwrap()
+        self.vars.get(key).unwrap_or_else(|| {
+            panic!("No key {} in config.rust (at {})", key, self.config_path)
+        })
+    }
+
+    /// Buffer a `cargo:` directive, to be emitted later by `flush()`.
+    fn emit(&self, directive : String) {
+        self.directives.borrow_mut().push(directive);
+    }
+
+    /// The buffered directives, deduped and in first-seen order.
+    fn deduped(&self) -> Vec<String> {
+        let mut seen = std::collections::HashSet::new();
+        let mut out = Vec::new();
+        for directive in self.directives.borrow().iter() {
+            if seen.insert(directive.clone()) {
+                out.push(directive.clone());
+            }
+        }
+        out
+    }
+
+    /// Print every buffered directive to stdout, in first-seen order,
+    /// dropping exact duplicates.  Call once, after everything else.
+    fn flush(&self) {
+        for directive in self.deduped() {
+            println!("{}", directive);
+        }
     }
 
     /// Add a dependency on a static C library that is part of Tor, by name.
     fn component(&self, s : &str) {
-        println!("cargo:rustc-link-lib=static={}", s);
+        self.emit(format!("cargo:rustc-link-lib=static={}", s));
     }
 
     /// Add a dependency on a native library that is not part of Tor, by name.
     fn dependency(&self, s : &str) {
-        println!("cargo:rustc-link-lib={}", s);
+        self.emit(format!("cargo:rustc-link-lib={}", s));
+    }
+
+    /// Add a dependency on a native library by its exact on-disk file name,
+    /// as produced by GNU ld's `-l:libfoo.a` syntax, rather than by the
+    /// usual abbreviated "-lfoo" name.
+    fn dependency_exact(&self, s : &str) {
+        let kind = if s.ends_with(".a") { "static" } else { "dylib" };
+        self.emit(format!("cargo:rustc-link-lib={}:+verbatim={}", kind, s));
+    }
+
+    /// Add a dependency on a macOS framework, by name.
+    fn framework(&self, s : &str) {
+        self.emit(format!("cargo:rustc-link-lib=framework={}", s));
+    }
+
+    /// Pass an opaque argument straight through to the linker.
+    fn link_arg(&self, s : &str) {
+        self.emit(format!("cargo:rustc-link-arg={}", s));
     }
 
     /// Add a link path, relative to Tor's build directory.
     fn link_relpath(&self, s : &str) {
         let builddir = self.get("BUILDDIR");
-        println!("cargo:rustc-link-search=native={}/{}", builddir, s);
+        self.emit(format!("cargo:rustc-link-search=native={}/{}", builddir, s));
     }
 
     /// Add an absolute link path.
     fn link_path(&self, s : &str) {
-        println!("cargo:rustc-link-search=native={}", s);
+        self.emit(format!("cargo:rustc-link-search=native={}", s));
     }
 
-    /// Parse the CFLAGS in s, looking for -l and -L items, and adding
-    /// rust configuration as appropriate.
+    /// Parse the CFLAGS in s, looking for linker flags, and adding rust
+    /// configuration as appropriate.
+    ///
+    /// Understands split and joined "-l"/"-L", quoted whitespace (so a
+    /// path containing spaces can be given as e.g. `-L"/opt/lib with
+    /// space"`), macOS "-framework Name", GNU ld's "-l:libfoo.a"
+    /// exact-name linking, and "-Wl,..." linker-argument passthrough.
+    /// Anything else is ignored.
     fn from_cflags(&self, s : &str) {
         let mut next_is_lib = false;
         let mut next_is_path = false;
-        for ent in self.get(s).split_whitespace() {
+        let mut next_is_framework = false;
+        let tokens = tokenize_flags(self.get(s));
+        for ent in tokens.iter().map(|t| t.as_str()) {
             if next_is_lib {
                 self.dependency(ent);
                 next_is_lib = false;
             } else if next_is_path {
                 self.link_path(ent);
                 next_is_path = false;
+            } else if next_is_framework {
+                self.framework(ent);
+                next_is_framework = false;
             } else if ent == "-l" {
                 next_is_lib = true;
             } else if ent == "-L" {
                 next_is_path = true;
-            } else if ent.starts_with("-L") {
-                self.link_path(&ent[2..]);
-            } else if ent.starts_with("-l") {
-                self.dependency(&ent[2..]);
+            } else if ent == "-framework" {
+                next_is_framework = true;
+            } else if ent.starts_with("-Wl,") {
+                self.link_arg(ent);
+            } else if let Some(rest) = ent.strip_prefix("-l:") {
+                self.dependency_exact(rest);
+            } else if let Some(rest) = ent.strip_prefix("-L") {
+                self.link_path(rest);
+            } else if let Some(rest) = ent.strip_prefix("-l") {
+                self.dependency(rest);
             }
         }
     }
@@ -122,67 +283,204 @@ impl Config {
 
 pub fn main() {
     let cfg = Config::load().unwrap();
-    let package = env::var("CARGO_PKG_NAME").unwrap();
-
-    match package.as_ref() {
-        "crypto" => {
-            // Right now, I'm having a separate configuration for each Rust
-            // package, since I'm hoping we can trim them down.  Once we have a
-            // second Rust package that needs to use this build script, let's
-            // extract some of this stuff into a module.
-            //
-            // This is a ridiculous amount of code to be pulling in just
-            // to test our crypto library: modularity would be our
-            // friend here.
-            cfg.from_cflags("TOR_LDFLAGS_zlib");
-            cfg.from_cflags("TOR_LDFLAGS_openssl");
-            cfg.from_cflags("TOR_LDFLAGS_libevent");
-
-            cfg.link_relpath("src/lib");
-            cfg.link_relpath("src/common");
-            cfg.link_relpath("src/ext/keccak-tiny");
-            cfg.link_relpath("src/ext/ed25519/ref10");
-            cfg.link_relpath("src/ext/ed25519/donna");
-            cfg.link_relpath("src/trunnel");
-
-            // Note that we can't pull in "libtor-testing", or else we
-            // will have dependencies on all the other rust packages that
-            // tor uses.  We must be careful with factoring and dependencies
-            // moving forward!
-            cfg.component("tor-crypt-ops-testing");
-            cfg.component("or-testing");
-            cfg.component("tor-log");
-            cfg.component("tor-lock");
-            cfg.component("tor-fdio");
-            cfg.component("tor-container-testing");
-            cfg.component("tor-smartlist-core-testing");
-            cfg.component("tor-string-testing");
-            cfg.component("tor-malloc");
-            cfg.component("tor-wallclock");
-            cfg.component("tor-err-testing");
-            cfg.component("or-event-testing");
-            cfg.component("tor-intmath-testing");
-            cfg.component("tor-ctime-testing");
-            cfg.component("curve25519_donna");
-            cfg.component("keccak-tiny");
-            cfg.component("ed25519_ref10");
-            cfg.component("ed25519_donna");
-            cfg.component("or-trunnel-testing");
-
-            cfg.from_cflags("TOR_ZLIB_LIBS");
-            cfg.from_cflags("TOR_LIB_MATH");
-            cfg.from_cflags("TOR_OPENSSL_LIBS");
-            cfg.from_cflags("TOR_LIBEVENT_LIBS");
-            cfg.from_cflags("TOR_LIB_WS32");
-            cfg.from_cflags("TOR_LIB_GDI");
-            cfg.from_cflags("TOR_LIB_USERENV");
-            cfg.from_cflags("CURVE25519_LIBS");
-            cfg.from_cflags("TOR_LZMA_LIBS");
-            cfg.from_cflags("TOR_ZSTD_LIBS");
-            cfg.from_cflags("LIBS");
-        },
-        _ => {
-            panic!("No configuration in build.rs for package {}", package);
+    let package = cfg.env_var("CARGO_PKG_NAME").unwrap();
+    let profile = cfg.link_profile();
+
+    // The link recipe is data-driven from per-package config.rust keys.
+    // RUST_CFLAGS_VARS is split into "pre"/"post" lists to preserve link
+    // order around the component list.
+    let components_key = format!("RUST_LINK_COMPONENTS_{}_{}", package, profile);
+    for cflags_var in cfg.get(&format!("RUST_CFLAGS_VARS_PRE_{}", package)).split_whitespace() {
+        cfg.from_cflags(cflags_var);
+    }
+    for component in cfg.get(&components_key).split_whitespace() {
+        cfg.component(component);
+    }
+    for dep in cfg.get(&format!("RUST_LINK_DEPS_{}", package)).split_whitespace() {
+        cfg.dependency(dep);
+    }
+    for relpath in cfg.get(&format!("RUST_LINK_RELPATHS_{}", package)).split_whitespace() {
+        cfg.link_relpath(relpath);
+    }
+    for cflags_var in cfg.get(&format!("RUST_CFLAGS_VARS_POST_{}", package)).split_whitespace() {
+        cfg.from_cflags(cflags_var);
+    }
+
+    cfg.emit_rerun_if_changed();
+    cfg.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Tests that set process environment variables share this lock, since
+    /// `cargo test` runs tests in parallel and the environment is global.
+    static ENV_MUTEX : Mutex<()> = Mutex::new(());
+
+    fn lock_env() -> std::sync::MutexGuard<'static, ()> {
+        ENV_MUTEX.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// A Config backed by the given key/value pairs, with no config.rust
+    /// file behind it.
+    fn config_with_vars(vars : HashMap<String,String>) -> Config {
+        Config {
+            vars,
+            directives: RefCell::new(Vec::new()),
+            config_path: "<test>".to_owned(),
+            env_vars: RefCell::new(Vec::new()),
         }
     }
+
+    /// A Config with no keys at all, for tests that only exercise
+    /// methods which don't call `get()`.
+    fn empty_config() -> Config {
+        config_with_vars(HashMap::new())
+    }
+
+    #[test]
+    fn tokenize_flags_splits_on_whitespace() {
+        assert_eq!(tokenize_flags("-lfoo -L/usr/lib"),
+                   vec!["-lfoo", "-L/usr/lib"]);
+    }
+
+    #[test]
+    fn tokenize_flags_keeps_whitespace_inside_quotes() {
+        assert_eq!(tokenize_flags("-L\"/opt/lib with space\" -lfoo"),
+                   vec!["-L/opt/lib with space", "-lfoo"]);
+        assert_eq!(tokenize_flags("-L'/opt/lib with space'"),
+                   vec!["-L/opt/lib with space"]);
+    }
+
+    /// A scratch directory under the system temp dir, unique to this test
+    /// process, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name : &str) -> TempDir {
+            let dir = env::temp_dir()
+                .join(format!("tor_build_rs_test_{}_{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn find_cfg_prefers_tor_rust_config_path_over_out_dir() {
+        let _guard = lock_env();
+        let dir = TempDir::new("preferred");
+        std::fs::write(dir.0.join("config.rust"), "").unwrap();
+
+        env::set_var("TOR_RUST_CONFIG_PATH", dir.0.to_str().unwrap());
+        env::set_var("OUT_DIR", "/nonexistent/out/dir/for/build-rs-test");
+
+        let mut env_vars = Vec::new();
+        let found = find_cfg(&mut env_vars).unwrap();
+
+        env::remove_var("TOR_RUST_CONFIG_PATH");
+        env::remove_var("OUT_DIR");
+
+        assert_eq!(found, dir.0.join("config.rust").to_str().unwrap());
+        assert!(env_vars.contains(&"TOR_RUST_CONFIG_PATH".to_owned()));
+    }
+
+    #[test]
+    fn find_cfg_walks_up_from_out_dir_when_config_path_is_unset() {
+        let _guard = lock_env();
+        let dir = TempDir::new("walk_up");
+        std::fs::write(dir.0.join("config.rust"), "").unwrap();
+        let nested = dir.0.join("a").join("b");
+        std::fs::create_dir_all(&nested).unwrap();
+
+        env::remove_var("TOR_RUST_CONFIG_PATH");
+        env::set_var("OUT_DIR", nested.to_str().unwrap());
+
+        let mut env_vars = Vec::new();
+        let found = find_cfg(&mut env_vars).unwrap();
+
+        env::remove_var("OUT_DIR");
+
+        assert_eq!(found, dir.0.join("config.rust").to_str().unwrap());
+    }
+
+    #[test]
+    fn find_cfg_error_lists_the_paths_it_searched() {
+        let _guard = lock_env();
+        let dir = TempDir::new("missing");
+
+        env::remove_var("TOR_RUST_CONFIG_PATH");
+        env::set_var("OUT_DIR", dir.0.to_str().unwrap());
+
+        let mut env_vars = Vec::new();
+        let err = find_cfg(&mut env_vars).unwrap_err();
+
+        env::remove_var("OUT_DIR");
+
+        let msg = err.to_string();
+        assert!(msg.contains("searched"));
+        assert!(msg.contains(dir.0.to_str().unwrap()));
+    }
+
+    #[test]
+    fn link_profile_defaults_to_production() {
+        let _guard = lock_env();
+        env::remove_var("RUST_LINK_PROFILE");
+        assert_eq!(empty_config().link_profile(), "production");
+    }
+
+    #[test]
+    fn link_profile_honors_rust_link_profile() {
+        let _guard = lock_env();
+        env::set_var("RUST_LINK_PROFILE", "testing");
+        assert_eq!(empty_config().link_profile(), "testing");
+        env::remove_var("RUST_LINK_PROFILE");
+    }
+
+    #[test]
+    fn from_cflags_dispatches_each_token_kind() {
+        let mut vars = HashMap::new();
+        vars.insert("TEST_FLAGS".to_owned(),
+                    "-framework Security -Wl,-rpath -l:libfoo.a -L/x -lbar".to_owned());
+        let cfg = config_with_vars(vars);
+        cfg.from_cflags("TEST_FLAGS");
+        assert_eq!(cfg.deduped(), vec![
+            "cargo:rustc-link-lib=framework=Security".to_owned(),
+            "cargo:rustc-link-arg=-Wl,-rpath".to_owned(),
+            "cargo:rustc-link-lib=static:+verbatim=libfoo.a".to_owned(),
+            "cargo:rustc-link-search=native=/x".to_owned(),
+            "cargo:rustc-link-lib=bar".to_owned(),
+        ]);
+    }
+
+    #[test]
+    fn deduped_drops_repeats_and_keeps_first_seen_order() {
+        let cfg = empty_config();
+        cfg.dependency("b");
+        cfg.dependency("a");
+        cfg.dependency("b");
+        assert_eq!(cfg.deduped(), vec![
+            "cargo:rustc-link-lib=b".to_owned(),
+            "cargo:rustc-link-lib=a".to_owned(),
+        ]);
+    }
+
+    #[test]
+    fn dependency_exact_picks_static_for_dot_a_suffix() {
+        let cfg = empty_config();
+        cfg.dependency_exact("libfoo.a");
+        cfg.dependency_exact("libfoo.so");
+        assert_eq!(*cfg.directives.borrow(), vec![
+            "cargo:rustc-link-lib=static:+verbatim=libfoo.a".to_owned(),
+            "cargo:rustc-link-lib=dylib:+verbatim=libfoo.so".to_owned(),
+        ]);
+    }
 }